@@ -0,0 +1,492 @@
+//! Parsing of command-line filter arguments into a [`Filter`].
+//!
+//! A filter is given on the command line as a boolean expression over a
+//! handful of leaf conditions, using `and`/`or`/`not` keywords and
+//! parenthesized groups for precedence, e.g. `+work and (+urgent or
+//! +today)`. Adjacent terms with no keyword between them default to `and`,
+//! so a plain list of tags like `+work +urgent` keeps meaning "both tags".
+//!
+//! Each leaf condition is one of:
+//!
+//!  - `+TAG` / `-TAG`, matching tasks with or without that tag;
+//!  - `+BLOCKED` / `+UNBLOCKED`, matching tasks that do or don't depend on
+//!    an incomplete task;
+//!  - `status:STATUS` / `-status:STATUS`, matching tasks with or without
+//!    that status (`pending`, `completed`, or `deleted`); or
+//!  - any other word, which is matched as a substring of the description.
+//!
+//! A task id -- a working-set index (`42`), a full uuid, or a uuid prefix --
+//! is not part of the expression; it instead selects that specific task, as
+//! if it had been passed to `task <id> ...`.
+//!
+//! A `sort:key1,key2,...` argument sets the result order: `id` (working-set
+//! id), `description`, `status`, or `+TAG` (tag presence), each optionally
+//! prefixed with `-` for descending, e.g. `sort:status,-id`.
+
+use failure::{bail, Fallible};
+use taskchampion::{Status, Uuid};
+
+/// A task identifier as given on the command line.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum TaskId {
+    /// An id in the working set, like `5` in `task 5 done`.
+    WorkingSetId(usize),
+    /// A prefix of a task's uuid.
+    PartialUuid(String),
+    /// A full task uuid.
+    Uuid(Uuid),
+}
+
+/// The set of tasks a filter's expression should be evaluated against.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Universe {
+    /// Tasks named explicitly on the command line.
+    IdList(Vec<TaskId>),
+    /// Every task in the replica.
+    AllTasks,
+    /// Tasks in the working set.
+    PendingTasks,
+    /// Tasks with a particular status.
+    Status(Status),
+}
+
+impl Default for Universe {
+    fn default() -> Self {
+        Universe::PendingTasks
+    }
+}
+
+/// A single leaf condition to test a task against.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Condition {
+    HasTag(String),
+    NoTag(String),
+    HasStatus(Status),
+    NoStatus(Status),
+    DescriptionContains(String),
+    Blocked,
+    Unblocked,
+}
+
+/// A boolean expression tree of [`Condition`]s.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Expr {
+    Cond(Condition),
+    And(Vec<Expr>),
+    Or(Vec<Expr>),
+    Not(Box<Expr>),
+}
+
+impl Default for Expr {
+    fn default() -> Self {
+        // an empty conjunction is vacuously true, matching every task
+        Expr::And(vec![])
+    }
+}
+
+/// An attribute to sort matched tasks by.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum SortAttr {
+    WorkingSetId,
+    Description,
+    Status,
+    Tag(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct SortKey {
+    pub(crate) attr: SortAttr,
+    pub(crate) ascending: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct Filter {
+    pub(crate) universe: Universe,
+    pub(crate) expr: Expr,
+    pub(crate) sort: Vec<SortKey>,
+}
+
+fn parse_status(word: &str) -> Option<Status> {
+    match word {
+        "pending" => Some(Status::Pending),
+        "completed" => Some(Status::Completed),
+        "deleted" => Some(Status::Deleted),
+        _ => None,
+    }
+}
+
+fn parse_task_id(word: &str) -> Option<TaskId> {
+    if let Ok(id) = word.parse::<usize>() {
+        return Some(TaskId::WorkingSetId(id));
+    }
+    if let Ok(uuid) = word.parse::<Uuid>() {
+        return Some(TaskId::Uuid(uuid));
+    }
+    // a partial uuid is a prefix of hex digits and dashes, long enough that
+    // it's unlikely to collide with an ordinary description word
+    if word.len() >= 8 && word.chars().all(|c| c.is_ascii_hexdigit() || c == '-') {
+        return Some(TaskId::PartialUuid(word.to_owned()));
+    }
+    None
+}
+
+fn parse_condition(word: &str) -> Fallible<Condition> {
+    if word == "+BLOCKED" {
+        return Ok(Condition::Blocked);
+    }
+    if word == "+UNBLOCKED" {
+        return Ok(Condition::Unblocked);
+    }
+    if let Some(tag) = word.strip_prefix('+') {
+        return Ok(Condition::HasTag(tag.to_owned()));
+    }
+    if let Some(rest) = word.strip_prefix("-status:") {
+        return match parse_status(rest) {
+            Some(status) => Ok(Condition::NoStatus(status)),
+            None => bail!("unknown status {:?}", rest),
+        };
+    }
+    if let Some(rest) = word.strip_prefix("status:") {
+        return match parse_status(rest) {
+            Some(status) => Ok(Condition::HasStatus(status)),
+            None => bail!("unknown status {:?}", rest),
+        };
+    }
+    if let Some(tag) = word.strip_prefix('-') {
+        return Ok(Condition::NoTag(tag.to_owned()));
+    }
+    Ok(Condition::DescriptionContains(word.to_owned()))
+}
+
+/// A token of the boolean expression grammar. `(` and `)` may arrive glued
+/// to an adjacent word, as a shell would pass `(+urgent` as a single
+/// argument for `(+urgent or +today)`, so tokenizing is more than a plain
+/// split on whitespace.
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    LParen,
+    RParen,
+    And,
+    Or,
+    Not,
+    Word(String),
+}
+
+fn tokenize(words: &[String]) -> Vec<Token> {
+    let mut tokens = vec![];
+    for word in words {
+        let mut s = word.as_str();
+        while let Some(rest) = s.strip_prefix('(') {
+            tokens.push(Token::LParen);
+            s = rest;
+        }
+        let mut trailing_rparens = 0;
+        while let Some(rest) = s.strip_suffix(')') {
+            s = rest;
+            trailing_rparens += 1;
+        }
+        if !s.is_empty() {
+            tokens.push(match s {
+                "and" => Token::And,
+                "or" => Token::Or,
+                "not" => Token::Not,
+                _ => Token::Word(s.to_owned()),
+            });
+        }
+        for _ in 0..trailing_rparens {
+            tokens.push(Token::RParen);
+        }
+    }
+    tokens
+}
+
+/// Recursive-descent parser for the expression grammar:
+/// ```text
+/// or-expr   := and-expr ( "or" and-expr )*
+/// and-expr  := unary ( ["and"] unary )*
+/// unary     := "not" unary | primary
+/// primary   := "(" or-expr ")" | WORD
+/// ```
+fn parse_or(tokens: &[Token], pos: &mut usize) -> Fallible<Expr> {
+    let mut terms = vec![parse_and(tokens, pos)?];
+    while matches!(tokens.get(*pos), Some(Token::Or)) {
+        *pos += 1;
+        terms.push(parse_and(tokens, pos)?);
+    }
+    Ok(if terms.len() == 1 {
+        terms.pop().unwrap()
+    } else {
+        Expr::Or(terms)
+    })
+}
+
+fn parse_and(tokens: &[Token], pos: &mut usize) -> Fallible<Expr> {
+    let mut terms = vec![parse_unary(tokens, pos)?];
+    loop {
+        match tokens.get(*pos) {
+            Some(Token::And) => {
+                *pos += 1;
+                terms.push(parse_unary(tokens, pos)?);
+            }
+            // adjacent terms with no keyword between them default to `and`
+            Some(Token::Word(_)) | Some(Token::Not) | Some(Token::LParen) => {
+                terms.push(parse_unary(tokens, pos)?);
+            }
+            _ => break,
+        }
+    }
+    Ok(if terms.len() == 1 {
+        terms.pop().unwrap()
+    } else {
+        Expr::And(terms)
+    })
+}
+
+fn parse_unary(tokens: &[Token], pos: &mut usize) -> Fallible<Expr> {
+    if matches!(tokens.get(*pos), Some(Token::Not)) {
+        *pos += 1;
+        return Ok(Expr::Not(Box::new(parse_unary(tokens, pos)?)));
+    }
+    parse_primary(tokens, pos)
+}
+
+fn parse_primary(tokens: &[Token], pos: &mut usize) -> Fallible<Expr> {
+    match tokens.get(*pos) {
+        Some(Token::LParen) => {
+            *pos += 1;
+            let expr = parse_or(tokens, pos)?;
+            match tokens.get(*pos) {
+                Some(Token::RParen) => {
+                    *pos += 1;
+                    Ok(expr)
+                }
+                _ => bail!("unmatched '(' in filter expression"),
+            }
+        }
+        Some(Token::Word(word)) => {
+            let cond = parse_condition(word)?;
+            *pos += 1;
+            Ok(Expr::Cond(cond))
+        }
+        other => bail!("expected a condition, found {:?}", other),
+    }
+}
+
+/// Does this expression consist of exactly one top-level `HasStatus`
+/// condition, with nothing else to narrow or widen it?
+fn as_sole_status(expr: &Expr) -> Option<Status> {
+    match expr {
+        Expr::Cond(Condition::HasStatus(status)) => Some(status.clone()),
+        _ => None,
+    }
+}
+
+/// Parse a single `sort:key1,key2,...` argument into sort keys.  A leading
+/// `-` on a key reverses that key's order, e.g. `sort:status,-id`.
+fn parse_sort(arg: &str) -> Fallible<Vec<SortKey>> {
+    let keys = arg.strip_prefix("sort:").expect("caller checked the prefix");
+    keys.split(',')
+        .map(|key| {
+            let (ascending, key) = match key.strip_prefix('-') {
+                Some(rest) => (false, rest),
+                None => (true, key),
+            };
+            let attr = if let Some(tag) = key.strip_prefix('+') {
+                SortAttr::Tag(tag.to_owned())
+            } else {
+                match key {
+                    "id" => SortAttr::WorkingSetId,
+                    "description" => SortAttr::Description,
+                    "status" => SortAttr::Status,
+                    _ => bail!("unknown sort key {:?}", key),
+                }
+            };
+            Ok(SortKey { attr, ascending })
+        })
+        .collect()
+}
+
+/// Parse a filter's command-line words into a [`Filter`].
+pub(crate) fn parse_filter(args: &[String]) -> Fallible<Filter> {
+    let mut ids = vec![];
+    let mut rest = vec![];
+    let mut sort = vec![];
+
+    for word in args {
+        if word.starts_with("sort:") {
+            sort.extend(parse_sort(word)?);
+            continue;
+        }
+        match parse_task_id(word) {
+            Some(id) => ids.push(id),
+            None => rest.push(word.clone()),
+        }
+    }
+
+    let tokens = tokenize(&rest);
+    let expr = if tokens.is_empty() {
+        Expr::default()
+    } else {
+        let mut pos = 0;
+        let expr = parse_or(&tokens, &mut pos)?;
+        if pos != tokens.len() {
+            bail!("unexpected trailing tokens in filter expression");
+        }
+        expr
+    };
+
+    let universe = if !ids.is_empty() {
+        Universe::IdList(ids)
+    } else if let Some(status) = as_sole_status(&expr) {
+        // a filter that is *only* a status condition can use the narrower
+        // Status universe, rather than scanning every task
+        Universe::Status(status)
+    } else {
+        Universe::PendingTasks
+    };
+
+    Ok(Filter {
+        universe,
+        expr,
+        sort,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn words(s: &str) -> Vec<String> {
+        s.split_whitespace().map(|w| w.to_owned()).collect()
+    }
+
+    #[test]
+    fn implicit_and() {
+        let filter = parse_filter(&words("+work +urgent")).unwrap();
+        assert_eq!(filter.universe, Universe::PendingTasks);
+        assert_eq!(
+            filter.expr,
+            Expr::And(vec![
+                Expr::Cond(Condition::HasTag("work".to_owned())),
+                Expr::Cond(Condition::HasTag("urgent".to_owned())),
+            ])
+        );
+    }
+
+    #[test]
+    fn explicit_or_and_grouping() {
+        let filter = parse_filter(&words("+work and (+urgent or +today)")).unwrap();
+        assert_eq!(
+            filter.expr,
+            Expr::And(vec![
+                Expr::Cond(Condition::HasTag("work".to_owned())),
+                Expr::Or(vec![
+                    Expr::Cond(Condition::HasTag("urgent".to_owned())),
+                    Expr::Cond(Condition::HasTag("today".to_owned())),
+                ]),
+            ])
+        );
+    }
+
+    #[test]
+    fn not_a_group() {
+        let filter = parse_filter(&words("not (+yes or +no)")).unwrap();
+        assert_eq!(
+            filter.expr,
+            Expr::Not(Box::new(Expr::Or(vec![
+                Expr::Cond(Condition::HasTag("yes".to_owned())),
+                Expr::Cond(Condition::HasTag("no".to_owned())),
+            ])))
+        );
+    }
+
+    #[test]
+    fn status_universe_narrowing() {
+        let filter = parse_filter(&words("status:completed")).unwrap();
+        assert_eq!(filter.universe, Universe::Status(Status::Completed));
+        assert_eq!(
+            filter.expr,
+            Expr::Cond(Condition::HasStatus(Status::Completed))
+        );
+    }
+
+    #[test]
+    fn description_text() {
+        let filter = parse_filter(&words("buy milk")).unwrap();
+        assert_eq!(
+            filter.expr,
+            Expr::And(vec![
+                Expr::Cond(Condition::DescriptionContains("buy".to_owned())),
+                Expr::Cond(Condition::DescriptionContains("milk".to_owned())),
+            ])
+        );
+    }
+
+    #[test]
+    fn working_set_id() {
+        let filter = parse_filter(&words("5")).unwrap();
+        assert_eq!(
+            filter.universe,
+            Universe::IdList(vec![TaskId::WorkingSetId(5)])
+        );
+    }
+
+    #[test]
+    fn blocked_and_unblocked() {
+        let filter = parse_filter(&words("+BLOCKED")).unwrap();
+        assert_eq!(filter.expr, Expr::Cond(Condition::Blocked));
+
+        let filter = parse_filter(&words("+UNBLOCKED")).unwrap();
+        assert_eq!(filter.expr, Expr::Cond(Condition::Unblocked));
+    }
+
+    #[test]
+    fn sort_keys() {
+        let filter = parse_filter(&words("+urgent sort:status,-id")).unwrap();
+        assert_eq!(
+            filter.sort,
+            vec![
+                SortKey {
+                    attr: SortAttr::Status,
+                    ascending: true,
+                },
+                SortKey {
+                    attr: SortAttr::WorkingSetId,
+                    ascending: false,
+                },
+            ]
+        );
+        // the sort spec isn't part of the boolean expression
+        assert_eq!(
+            filter.expr,
+            Expr::Cond(Condition::HasTag("urgent".to_owned()))
+        );
+    }
+
+    #[test]
+    fn sort_tag_key() {
+        let filter = parse_filter(&words("sort:+urgent")).unwrap();
+        assert_eq!(
+            filter.sort,
+            vec![SortKey {
+                attr: SortAttr::Tag("urgent".to_owned()),
+                ascending: true,
+            }]
+        );
+    }
+
+    #[test]
+    fn unknown_sort_key_is_an_error() {
+        assert!(parse_filter(&words("sort:bogus")).is_err());
+    }
+
+    #[test]
+    fn unmatched_paren_is_an_error() {
+        assert!(parse_filter(&words("(+yes")).is_err());
+    }
+
+    #[test]
+    fn unknown_status_is_an_error() {
+        assert!(parse_filter(&words("status:bogus")).is_err());
+    }
+}
@@ -1,29 +1,182 @@
-use crate::argparse::{Condition, Filter, TaskId, Universe};
+use crate::argparse::{Condition, Expr, Filter, SortAttr, SortKey, TaskId, Universe};
 use failure::Fallible;
-use std::collections::HashSet;
+use roaring::RoaringBitmap;
+use std::collections::{HashMap, HashSet};
 use std::convert::TryInto;
-use taskchampion::{Replica, Tag, Task};
-
-fn match_task(filter: &Filter, task: &Task) -> bool {
-    for cond in &filter.conditions {
-        match cond {
-            Condition::HasTag(ref tag) => {
-                // see #111 for the unwrap
-                let tag: Tag = tag.try_into().unwrap();
-                if !task.has_tag(&tag) {
-                    return false;
+use taskchampion::{Replica, Status, Tag, Task, Uuid};
+
+/// An inverted index from tag name to the set of tasks carrying that tag,
+/// keyed by a dense integer id assigned to each task uuid.  This lets
+/// `Condition::HasTag`/`NoTag` filtering over `Universe::AllTasks` be
+/// resolved with bitmap set algebra instead of a per-task scan.
+struct TagIndex {
+    /// task uuid -> dense id
+    ids: HashMap<Uuid, u32>,
+    /// every id assigned by this index
+    all_ids: RoaringBitmap,
+    /// tag name -> bitmap of ids for tasks carrying that tag
+    tags: HashMap<String, RoaringBitmap>,
+}
+
+impl TagIndex {
+    fn build<'a>(tasks: impl Iterator<Item = &'a Task>) -> TagIndex {
+        let mut ids = HashMap::new();
+        let mut all_ids = RoaringBitmap::new();
+        let mut tags: HashMap<String, RoaringBitmap> = HashMap::new();
+
+        for task in tasks {
+            let id = ids.len() as u32;
+            let uuid = *task.get_uuid();
+            ids.insert(uuid, id);
+            all_ids.insert(id);
+            for tag in task.get_tags() {
+                tags.entry(tag.to_string()).or_default().insert(id);
+            }
+        }
+
+        TagIndex {
+            ids,
+            all_ids,
+            tags,
+        }
+    }
+
+    /// Resolve a filter expression against this index, returning the matching
+    /// ids.  Returns `None` if some part of the expression could not be
+    /// resolved using the index alone, in which case the caller should fall
+    /// back to `match_task`.
+    fn resolve(&self, expr: &Expr) -> Option<RoaringBitmap> {
+        match expr {
+            Expr::Cond(Condition::HasTag(ref tag)) => Some(match self.tags.get(tag) {
+                Some(bitmap) => bitmap.clone(),
+                // no task has this tag, so nothing can match
+                None => RoaringBitmap::new(),
+            }),
+            Expr::Cond(Condition::NoTag(ref tag)) => {
+                let mut matched = self.all_ids.clone();
+                // a tag nobody has is a no-op to exclude
+                if let Some(bitmap) = self.tags.get(tag) {
+                    matched -= bitmap;
+                }
+                Some(matched)
+            }
+            // the index only knows about tags, so anything else forces a fall back
+            Expr::Cond(_) => None,
+            Expr::And(ref exprs) => {
+                let mut matched = self.all_ids.clone();
+                for e in exprs {
+                    matched &= self.resolve(e)?;
                 }
+                Some(matched)
             }
-            Condition::NoTag(ref tag) => {
-                // see #111 for the unwrap
-                let tag: Tag = tag.try_into().unwrap();
-                if task.has_tag(&tag) {
-                    return false;
+            Expr::Or(ref exprs) => {
+                let mut matched = RoaringBitmap::new();
+                for e in exprs {
+                    matched |= self.resolve(e)?;
                 }
+                Some(matched)
             }
+            Expr::Not(ref e) => {
+                let mut matched = self.all_ids.clone();
+                matched -= self.resolve(e)?;
+                Some(matched)
+            }
+        }
+    }
+}
+
+/// Is this task blocked, i.e. does it depend on a task that is still pending?
+fn is_blocked(task: &Task, statuses: &HashMap<Uuid, Status>) -> bool {
+    task.get_dependencies()
+        .any(|dep| statuses.get(&dep) == Some(&Status::Pending))
+}
+
+fn match_condition(cond: &Condition, task: &Task, statuses: &HashMap<Uuid, Status>) -> bool {
+    match cond {
+        Condition::HasTag(ref tag) => {
+            // see #111 for the unwrap
+            let tag: Tag = tag.try_into().unwrap();
+            task.has_tag(&tag)
+        }
+        Condition::NoTag(ref tag) => {
+            // see #111 for the unwrap
+            let tag: Tag = tag.try_into().unwrap();
+            !task.has_tag(&tag)
+        }
+        Condition::HasStatus(ref status) => task.get_status() == *status,
+        Condition::NoStatus(ref status) => task.get_status() != *status,
+        Condition::DescriptionContains(ref needle) => {
+            task.get_description().contains(needle.as_str())
+        }
+        Condition::Blocked => is_blocked(task, statuses),
+        Condition::Unblocked => !is_blocked(task, statuses),
+    }
+}
+
+fn match_task(filter: &Filter, task: &Task, statuses: &HashMap<Uuid, Status>) -> bool {
+    fn eval(expr: &Expr, task: &Task, statuses: &HashMap<Uuid, Status>) -> bool {
+        match expr {
+            Expr::Cond(cond) => match_condition(cond, task, statuses),
+            Expr::And(exprs) => exprs.iter().all(|e| eval(e, task, statuses)),
+            Expr::Or(exprs) => exprs.iter().any(|e| eval(e, task, statuses)),
+            Expr::Not(expr) => !eval(expr, task, statuses),
+        }
+    }
+    eval(&filter.expr, task, statuses)
+}
+
+/// Does this filter expression need per-task dependency statuses to evaluate?
+fn needs_dependency_statuses(expr: &Expr) -> bool {
+    match expr {
+        Expr::Cond(Condition::Blocked) | Expr::Cond(Condition::Unblocked) => true,
+        Expr::Cond(_) => false,
+        Expr::And(exprs) | Expr::Or(exprs) => exprs.iter().any(needs_dependency_statuses),
+        Expr::Not(expr) => needs_dependency_statuses(expr),
+    }
+}
+
+/// Rank statuses for sorting purposes; unrecognized statuses sort last.
+fn status_rank(status: &Status) -> u8 {
+    match status {
+        Status::Pending => 0,
+        Status::Completed => 1,
+        Status::Deleted => 2,
+        _ => 3,
+    }
+}
+
+/// Compare two tasks on a single sort attribute, ignoring direction.
+fn compare_by(
+    attr: &SortAttr,
+    working_set_index: &HashMap<Uuid, usize>,
+    a: &Task,
+    b: &Task,
+) -> std::cmp::Ordering {
+    match attr {
+        SortAttr::WorkingSetId => {
+            let a_id = working_set_index.get(a.get_uuid());
+            let b_id = working_set_index.get(b.get_uuid());
+            a_id.cmp(&b_id)
+        }
+        SortAttr::Description => a.get_description().cmp(b.get_description()),
+        SortAttr::Status => status_rank(&a.get_status()).cmp(&status_rank(&b.get_status())),
+        SortAttr::Tag(ref tag) => {
+            // see #111 for the unwrap
+            let tag: Tag = tag.as_str().try_into().unwrap();
+            a.has_tag(&tag).cmp(&b.has_tag(&tag))
         }
     }
-    true
+}
+
+/// Snapshot the working set as a uuid -> index map, for callers that need to
+/// resolve several uuids against it without re-reading it each time (#108).
+fn working_set_index(replica: &mut Replica) -> Fallible<HashMap<Uuid, usize>> {
+    Ok(replica
+        .working_set()?
+        .iter()
+        .enumerate()
+        .filter_map(|(i, task)| task.as_ref().map(|task| (*task.get_uuid(), i)))
+        .collect())
 }
 
 /// Return the tasks matching the given filter.  This will return each matching
@@ -42,24 +195,42 @@ pub(super) fn filtered_tasks(
         }
     }
 
+    // shared across the partial-uuid id-list branch and the working-set-id
+    // sort key below, so the uuid -> working-set-index map is built at most
+    // once per call
+    let mut working_set_index_cache: Option<HashMap<Uuid, usize>> = None;
+
+    // Condition::Blocked/Unblocked need every task's status to resolve dependencies;
+    // only pay for that scan when the filter actually uses them.
+    let statuses: HashMap<Uuid, Status> = if needs_dependency_statuses(&filter.expr) {
+        replica
+            .all_tasks()?
+            .iter()
+            .map(|(uuid, task)| (*uuid, task.get_status()))
+            .collect()
+    } else {
+        HashMap::new()
+    };
+
     // We will enumerate the universe of tasks for this filter, checking
     // each resulting task with match_task
     match filter.universe {
         // A list of IDs, but some are partial so we need to iterate over
         // all tasks and pattern-match their Uuids
         Universe::IdList(ref ids) if ids.iter().any(is_partial_uuid) => {
+            if working_set_index_cache.is_none() {
+                working_set_index_cache = Some(working_set_index(replica)?);
+            }
+            let working_set_index = working_set_index_cache.as_ref().unwrap();
+
             'task: for (uuid, task) in replica.all_tasks()?.drain() {
                 for id in ids {
                     if match id {
-                        TaskId::WorkingSetId(id) => {
-                            // NOTE: (#108) this results in many reads of the working set; it
-                            // may be better to cache this information here or in the Replica.
-                            replica.get_working_set_index(&uuid)? == Some(*id)
-                        }
+                        TaskId::WorkingSetId(id) => working_set_index.get(&uuid) == Some(id),
                         TaskId::PartialUuid(prefix) => uuid.to_string().starts_with(prefix),
                         TaskId::Uuid(id) => id == &uuid,
                     } {
-                        if match_task(filter, &task) {
+                        if match_task(filter, &task, &statuses) {
                             res.push(task);
                             continue 'task;
                         }
@@ -88,18 +259,34 @@ pub(super) fn filtered_tasks(
                     }
                     seen.insert(uuid);
 
-                    if match_task(filter, &task) {
+                    if match_task(filter, &task, &statuses) {
                         res.push(task);
                     }
                 }
             }
         }
 
-        // All tasks -- iterate over the full set
+        // All tasks -- use the tag inverted index to avoid a per-task scan
         Universe::AllTasks => {
-            for (_, task) in replica.all_tasks()?.drain() {
-                if match_task(filter, &task) {
-                    res.push(task);
+            let all_tasks: Vec<(Uuid, Task)> = replica.all_tasks()?.drain().collect();
+            let index = TagIndex::build(all_tasks.iter().map(|(_, task)| task));
+
+            match index.resolve(&filter.expr) {
+                Some(matched) => {
+                    for (uuid, task) in all_tasks {
+                        let id = index.ids[&uuid];
+                        if matched.contains(id) {
+                            res.push(task);
+                        }
+                    }
+                }
+                // the expression couldn't be resolved from the index; fall back to the linear scan
+                None => {
+                    for (_, task) in all_tasks {
+                        if match_task(filter, &task, &statuses) {
+                            res.push(task);
+                        }
+                    }
                 }
             }
         }
@@ -108,13 +295,59 @@ pub(super) fn filtered_tasks(
         Universe::PendingTasks => {
             for task in replica.working_set()?.drain(..) {
                 if let Some(task) = task {
-                    if match_task(filter, &task) {
+                    if match_task(filter, &task, &statuses) {
+                        res.push(task);
+                    }
+                }
+            }
+        }
+
+        // Tasks of a particular status -- scan, checking status first so the
+        // remaining conditions are only evaluated for tasks that can match
+        // A single status -- bucket all tasks by status first, so we only
+        // ever run match_task over the relevant subset
+        Universe::Status(ref status) => {
+            let mut by_status: HashMap<Status, Vec<Task>> = HashMap::new();
+            for (_, task) in replica.all_tasks()?.drain() {
+                by_status.entry(task.get_status()).or_default().push(task);
+            }
+            if let Some(tasks) = by_status.remove(status) {
+                for task in tasks {
+                    if match_task(filter, &task, &statuses) {
                         res.push(task);
                     }
                 }
             }
         }
     }
+
+    if !filter.sort.is_empty() {
+        if working_set_index_cache.is_none()
+            && filter
+                .sort
+                .iter()
+                .any(|key| matches!(key.attr, SortAttr::WorkingSetId))
+        {
+            working_set_index_cache = Some(working_set_index(replica)?);
+        }
+        let empty_working_set_index = HashMap::new();
+        let working_set_index = working_set_index_cache
+            .as_ref()
+            .unwrap_or(&empty_working_set_index);
+
+        // sort by the last key first, relying on a stable sort to combine the keys
+        for key in filter.sort.iter().rev() {
+            res.sort_by(|a, b| {
+                let ordering = compare_by(&key.attr, working_set_index, a, b);
+                if key.ascending {
+                    ordering
+                } else {
+                    ordering.reverse()
+                }
+            });
+        }
+    }
+
     Ok(res.into_iter())
 }
 
@@ -122,7 +355,6 @@ pub(super) fn filtered_tasks(
 mod test {
     use super::*;
     use crate::invocation::test::*;
-    use taskchampion::Status;
 
     #[test]
     fn exact_ids() {
@@ -228,7 +460,7 @@ mod test {
         // look for just "yes" (A and B)
         let filter = Filter {
             universe: Universe::AllTasks,
-            conditions: vec![Condition::HasTag("yes".to_owned())],
+            expr: Expr::Cond(Condition::HasTag("yes".to_owned())),
             ..Default::default()
         };
         let mut filtered: Vec<_> = filtered_tasks(&mut replica, &filter)?
@@ -240,7 +472,7 @@ mod test {
         // look for tags without "no" (A, D)
         let filter = Filter {
             universe: Universe::AllTasks,
-            conditions: vec![Condition::NoTag("no".to_owned())],
+            expr: Expr::Cond(Condition::NoTag("no".to_owned())),
             ..Default::default()
         };
         let mut filtered: Vec<_> = filtered_tasks(&mut replica, &filter)?
@@ -252,10 +484,10 @@ mod test {
         // look for tags with "yes" and "no" (B)
         let filter = Filter {
             universe: Universe::AllTasks,
-            conditions: vec![
-                Condition::HasTag("yes".to_owned()),
-                Condition::HasTag("no".to_owned()),
-            ],
+            expr: Expr::And(vec![
+                Expr::Cond(Condition::HasTag("yes".to_owned())),
+                Expr::Cond(Condition::HasTag("no".to_owned())),
+            ]),
             ..Default::default()
         };
         let filtered: Vec<_> = filtered_tasks(&mut replica, &filter)?
@@ -263,6 +495,38 @@ mod test {
             .collect();
         assert_eq!(vec!["B".to_owned()], filtered);
 
+        // look for tags with "yes" or "no" (A, B, C)
+        let filter = Filter {
+            universe: Universe::AllTasks,
+            expr: Expr::Or(vec![
+                Expr::Cond(Condition::HasTag("yes".to_owned())),
+                Expr::Cond(Condition::HasTag("no".to_owned())),
+            ]),
+            ..Default::default()
+        };
+        let mut filtered: Vec<_> = filtered_tasks(&mut replica, &filter)?
+            .map(|t| t.get_description().to_owned())
+            .collect();
+        filtered.sort();
+        assert_eq!(
+            vec!["A".to_owned(), "B".to_owned(), "C".to_owned()],
+            filtered
+        );
+
+        // look for tags that are not "no" and not "yes" (D)
+        let filter = Filter {
+            universe: Universe::AllTasks,
+            expr: Expr::Not(Box::new(Expr::Or(vec![
+                Expr::Cond(Condition::HasTag("yes".to_owned())),
+                Expr::Cond(Condition::HasTag("no".to_owned())),
+            ]))),
+            ..Default::default()
+        };
+        let filtered: Vec<_> = filtered_tasks(&mut replica, &filter)?
+            .map(|t| t.get_description().to_owned())
+            .collect();
+        assert_eq!(vec!["D".to_owned()], filtered);
+
         Ok(())
     }
 
@@ -286,4 +550,234 @@ mod test {
         filtered.sort();
         assert_eq!(vec!["A".to_owned()], filtered);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn status_filtering() {
+        let mut replica = test_replica();
+
+        replica.new_task(Status::Pending, "A".to_owned()).unwrap();
+        replica.new_task(Status::Completed, "B".to_owned()).unwrap();
+        replica.new_task(Status::Deleted, "C".to_owned()).unwrap();
+        replica.gc().unwrap();
+
+        // HasStatus(Completed) over AllTasks
+        let filter = Filter {
+            universe: Universe::AllTasks,
+            expr: Expr::Cond(Condition::HasStatus(Status::Completed)),
+            ..Default::default()
+        };
+        let filtered: Vec<_> = filtered_tasks(&mut replica, &filter)
+            .unwrap()
+            .map(|t| t.get_description().to_owned())
+            .collect();
+        assert_eq!(vec!["B".to_owned()], filtered);
+
+        // NoStatus(Deleted) over AllTasks
+        let filter = Filter {
+            universe: Universe::AllTasks,
+            expr: Expr::Cond(Condition::NoStatus(Status::Deleted)),
+            ..Default::default()
+        };
+        let mut filtered: Vec<_> = filtered_tasks(&mut replica, &filter)
+            .unwrap()
+            .map(|t| t.get_description().to_owned())
+            .collect();
+        filtered.sort();
+        assert_eq!(vec!["A".to_owned(), "B".to_owned()], filtered);
+
+        // the Status universe iterates only the relevant subset
+        let filter = Filter {
+            universe: Universe::Status(Status::Deleted),
+            ..Default::default()
+        };
+        let filtered: Vec<_> = filtered_tasks(&mut replica, &filter)
+            .unwrap()
+            .map(|t| t.get_description().to_owned())
+            .collect();
+        assert_eq!(vec!["C".to_owned()], filtered);
+    }
+
+    #[test]
+    fn description_contains() {
+        let mut replica = test_replica();
+
+        replica
+            .new_task(Status::Pending, "buy milk".to_owned())
+            .unwrap();
+        replica
+            .new_task(Status::Pending, "buy eggs".to_owned())
+            .unwrap();
+        replica
+            .new_task(Status::Pending, "walk the dog".to_owned())
+            .unwrap();
+        replica.gc().unwrap();
+
+        let filter = Filter {
+            universe: Universe::AllTasks,
+            expr: Expr::Cond(Condition::DescriptionContains("buy".to_owned())),
+            ..Default::default()
+        };
+        let mut filtered: Vec<_> = filtered_tasks(&mut replica, &filter)
+            .unwrap()
+            .map(|t| t.get_description().to_owned())
+            .collect();
+        filtered.sort();
+        assert_eq!(vec!["buy eggs".to_owned(), "buy milk".to_owned()], filtered);
+    }
+
+    #[test]
+    fn sorted_results() {
+        let mut replica = test_replica();
+
+        replica.new_task(Status::Pending, "C".to_owned()).unwrap();
+        replica.new_task(Status::Pending, "A".to_owned()).unwrap();
+        replica.new_task(Status::Pending, "B".to_owned()).unwrap();
+        replica.gc().unwrap();
+
+        let filter = Filter {
+            universe: Universe::AllTasks,
+            sort: vec![SortKey {
+                attr: SortAttr::Description,
+                ascending: true,
+            }],
+            ..Default::default()
+        };
+        let filtered: Vec<_> = filtered_tasks(&mut replica, &filter)
+            .unwrap()
+            .map(|t| t.get_description().to_owned())
+            .collect();
+        assert_eq!(
+            vec!["A".to_owned(), "B".to_owned(), "C".to_owned()],
+            filtered
+        );
+
+        // descending order reverses it
+        let filter = Filter {
+            universe: Universe::AllTasks,
+            sort: vec![SortKey {
+                attr: SortAttr::Description,
+                ascending: false,
+            }],
+            ..Default::default()
+        };
+        let filtered: Vec<_> = filtered_tasks(&mut replica, &filter)
+            .unwrap()
+            .map(|t| t.get_description().to_owned())
+            .collect();
+        assert_eq!(
+            vec!["C".to_owned(), "B".to_owned(), "A".to_owned()],
+            filtered
+        );
+
+        // pending-by-working-set-id order, as used by the CLI's default listing
+        let filter = Filter {
+            universe: Universe::PendingTasks,
+            sort: vec![SortKey {
+                attr: SortAttr::WorkingSetId,
+                ascending: true,
+            }],
+            ..Default::default()
+        };
+        let filtered: Vec<_> = filtered_tasks(&mut replica, &filter)
+            .unwrap()
+            .map(|t| t.get_description().to_owned())
+            .collect();
+        assert_eq!(
+            vec!["C".to_owned(), "A".to_owned(), "B".to_owned()],
+            filtered
+        );
+    }
+
+    #[test]
+    fn sorted_results_multi_key() {
+        let mut replica = test_replica();
+
+        // two tasks share a status, so the description is needed to break the tie
+        replica.new_task(Status::Pending, "B".to_owned()).unwrap();
+        replica
+            .new_task(Status::Completed, "A".to_owned())
+            .unwrap();
+        replica.new_task(Status::Pending, "A".to_owned()).unwrap();
+        replica.gc().unwrap();
+
+        // sort by status first, then description -- within each status bucket,
+        // descriptions should come out in order
+        let filter = Filter {
+            universe: Universe::AllTasks,
+            sort: vec![
+                SortKey {
+                    attr: SortAttr::Status,
+                    ascending: true,
+                },
+                SortKey {
+                    attr: SortAttr::Description,
+                    ascending: true,
+                },
+            ],
+            ..Default::default()
+        };
+        let filtered: Vec<_> = filtered_tasks(&mut replica, &filter)
+            .unwrap()
+            .map(|t| t.get_description().to_owned())
+            .collect();
+        assert_eq!(
+            vec!["A".to_owned(), "B".to_owned(), "A".to_owned()],
+            filtered
+        );
+    }
+
+    #[test]
+    fn blocked_and_unblocked() -> Fallible<()> {
+        let mut replica = test_replica();
+
+        let dep = replica.new_task(Status::Pending, "dep".to_owned())?;
+        let dep_uuid = *dep.get_uuid();
+        let mut blocked = replica
+            .new_task(Status::Pending, "blocked".to_owned())?
+            .into_mut(&mut replica);
+        blocked.add_dependency(dep_uuid)?;
+        replica.new_task(Status::Pending, "free".to_owned())?;
+
+        // +BLOCKED matches tasks depending on a still-pending task
+        let filter = Filter {
+            universe: Universe::AllTasks,
+            expr: Expr::Cond(Condition::Blocked),
+            ..Default::default()
+        };
+        let filtered: Vec<_> = filtered_tasks(&mut replica, &filter)?
+            .map(|t| t.get_description().to_owned())
+            .collect();
+        assert_eq!(vec!["blocked".to_owned()], filtered);
+
+        // +UNBLOCKED matches everything else
+        let filter = Filter {
+            universe: Universe::AllTasks,
+            expr: Expr::Cond(Condition::Unblocked),
+            ..Default::default()
+        };
+        let mut filtered: Vec<_> = filtered_tasks(&mut replica, &filter)?
+            .map(|t| t.get_description().to_owned())
+            .collect();
+        filtered.sort();
+        assert_eq!(vec!["dep".to_owned(), "free".to_owned()], filtered);
+
+        // once the dependency completes, the dependent task is unblocked
+        replica
+            .get_task(&dep_uuid)?
+            .unwrap()
+            .into_mut(&mut replica)
+            .set_status(Status::Completed)?;
+
+        let filter = Filter {
+            universe: Universe::AllTasks,
+            expr: Expr::Cond(Condition::Blocked),
+            ..Default::default()
+        };
+        let filtered: Vec<_> = filtered_tasks(&mut replica, &filter)?
+            .map(|t| t.get_description().to_owned())
+            .collect();
+        assert_eq!(Vec::<String>::new(), filtered);
+
+        Ok(())
+    }
+}